@@ -0,0 +1,90 @@
+use anyhow::{bail, Context};
+use std::convert::TryInto;
+use std::sync::Arc;
+use surf::{Client, Config, Url};
+
+use crate::surf2anyhow;
+
+/// A `surf::Client` pre-configured from the standard `CONSUL_HTTP_*`
+/// environment variables: it carries the agent's base URL, attaches
+/// `CONSUL_HTTP_TOKEN` as an `X-Consul-Token` header on every request, and,
+/// for `https://` endpoints (or `CONSUL_HTTP_SSL=1`), speaks TLS via rustls
+/// with manually-loaded roots from `CONSUL_CACERT` rather than trusting
+/// whatever the platform happens to ship.
+#[derive(Clone)]
+pub struct ConsulClient {
+    client: Client,
+}
+
+impl ConsulClient {
+    pub fn from_env() -> anyhow::Result<ConsulClient> {
+        let base_url = std::env::var("CONSUL_HTTP_ADDR")
+            .ok()
+            .unwrap_or_else(|| "http://localhost:8500".to_string());
+
+        let use_tls = base_url.starts_with("https://")
+            || std::env::var("CONSUL_HTTP_SSL")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false);
+
+        let mut config = Config::new()
+            .set_base_url(Url::parse(&base_url).context("Parsing CONSUL_HTTP_ADDR")?);
+
+        if let Ok(token) = std::env::var("CONSUL_HTTP_TOKEN") {
+            config = config
+                .add_header("X-Consul-Token", token)
+                .context("Setting X-Consul-Token header")?;
+        }
+
+        if use_tls {
+            config = config.set_tls_config(Some(Arc::new(manual_roots_tls_config()?)));
+        }
+
+        let client: Client = config.try_into().context("Building Consul HTTP client")?;
+
+        Ok(ConsulClient { client })
+    }
+
+    pub async fn get(&self, path: &str) -> anyhow::Result<surf::Response> {
+        surf2anyhow(self.client.get(path).await)
+    }
+
+    pub fn put(&self, path: &str) -> surf::RequestBuilder {
+        self.client.put(path)
+    }
+}
+
+/// Build a rustls `ClientConfig` whose root store is loaded solely from
+/// `CONSUL_CACERT` (PEM), mirroring the `rustls-tls-manual-roots` approach
+/// rather than pulling in the OS/webpki trust anchors.
+///
+/// `CONSUL_CACERT` is required here: with manual roots and no CA loaded, the
+/// root store would be empty and every handshake would fail with an opaque
+/// unknown-CA error, so we bail with a clear message instead.
+fn manual_roots_tls_config() -> anyhow::Result<rustls::ClientConfig> {
+    let ca_path = std::env::var("CONSUL_CACERT").context(
+        "CONSUL_HTTP_SSL/https:// requires CONSUL_CACERT to be set (manual TLS roots, no platform trust store is consulted)"
+    )?;
+
+    let pem = std::fs::read(&ca_path)
+        .with_context(|| format!("Reading CONSUL_CACERT at {}", ca_path))?;
+    let mut reader = std::io::BufReader::new(&pem[..]);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .context("Parsing CONSUL_CACERT as PEM")?;
+
+    if certs.is_empty() {
+        bail!("CONSUL_CACERT at {} contained no PEM certificates", ca_path);
+    }
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in certs {
+        roots
+            .add(&rustls::Certificate(cert))
+            .context("Adding CONSUL_CACERT entry to manual root store")?;
+    }
+
+    Ok(rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
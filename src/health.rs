@@ -0,0 +1,73 @@
+use anyhow::bail;
+use serde_derive::Deserialize;
+
+use crate::consul::ConsulClient;
+use crate::Requirement;
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct HealthServiceEntry {
+    Service: HealthServiceInfo,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct HealthServiceInfo {
+    Tags: Vec<String>,
+}
+
+/// Confirm that every `Requirement` has at least one passing Consul catalog
+/// instance advertising the requested version as a tag. Collects every
+/// problem it finds (rather than bailing on the first) so `--require-healthy`
+/// surfaces a single, complete report instead of one service at a time.
+pub async fn require_healthy(client: &ConsulClient, requirements: &[Requirement]) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+
+    for requirement in requirements {
+        let mut response = client
+            .get(&format!("/v1/health/service/{}?passing=true", requirement.name))
+            .await?;
+
+        if response.status().as_u16() != 200 {
+            problems.push(format!(
+                "{}: Consul health API returned {}",
+                requirement.name,
+                response.status()
+            ));
+            continue;
+        }
+
+        let entries: Vec<HealthServiceEntry> = response.body_json().await?;
+
+        if entries.is_empty() {
+            problems.push(format!("{}: no passing instances registered", requirement.name));
+            continue;
+        }
+
+        let matches = entries
+            .iter()
+            .any(|entry| entry.Service.Tags.iter().any(|tag| tag == &requirement.version));
+
+        if !matches {
+            let advertised: Vec<&str> = entries
+                .iter()
+                .flat_map(|entry| entry.Service.Tags.iter().map(|tag| &tag[..]))
+                .collect();
+
+            problems.push(format!(
+                "{}: wants version {}, passing instances advertise {:?} instead",
+                requirement.name, requirement.version, advertised
+            ));
+        }
+    }
+
+    if !problems.is_empty() {
+        bail!(
+            "Refusing to emit chart with --require-healthy set; {} service(s) not deployable:\n{}",
+            problems.len(),
+            problems.join("\n")
+        );
+    }
+
+    Ok(())
+}
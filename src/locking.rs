@@ -0,0 +1,177 @@
+use anyhow::{bail, Context};
+use async_std::task;
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::consul::ConsulClient;
+use crate::surf2anyhow;
+
+#[derive(Serialize)]
+struct SessionCreateRequest {
+    #[serde(rename = "TTL")]
+    ttl: String,
+    #[serde(rename = "Behavior")]
+    behavior: String,
+    #[serde(rename = "LockDelay")]
+    lock_delay: String,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SessionCreateResponse {
+    ID: String,
+}
+
+/// Holds a Consul session-backed lock on a single KV key, renewing the
+/// session in the background for as long as the guard is alive.
+///
+/// Call `release` to tear the lock down cleanly; if a `Lock` is dropped
+/// without being released, the session will simply expire after its TTL
+/// (Consul's `Behavior: delete` will remove the lock key for us), but we
+/// can't await the explicit release from `Drop`, so we log instead.
+pub struct Lock {
+    client: ConsulClient,
+    key: String,
+    session_id: String,
+    renew_handle: Option<task::JoinHandle<()>>,
+    released: bool,
+}
+
+impl Lock {
+    /// Acquire the lock at `/v1/kv/<key>/.lock`, blocking (via polling) for
+    /// up to `lock_timeout` before giving up.
+    pub async fn acquire(
+        client: &ConsulClient,
+        key: &str,
+        ttl: Duration,
+        lock_timeout: Duration,
+    ) -> anyhow::Result<Lock> {
+        let session_id = create_session(client, ttl).await?;
+        let lock_path = format!("/v1/kv/{}/.lock", key);
+
+        // Renew from the moment the session exists, not just once we've won
+        // the lock: the acquire poll below can itself run for up to
+        // `lock_timeout`, and a contended lock with `lock_timeout` >= `ttl`
+        // would otherwise let Consul expire our session before we ever
+        // acquire it.
+        let renew_handle = spawn_renewal(client.clone(), session_id.clone(), ttl);
+
+        let deadline = std::time::Instant::now() + lock_timeout;
+        loop {
+            let mut response = surf2anyhow(
+                client.put(&format!("{}?acquire={}", &lock_path, &session_id)).await,
+            )?;
+
+            let acquired: bool = response.body_json().await.unwrap_or(false);
+            if acquired {
+                break;
+            }
+
+            if std::time::Instant::now() >= deadline {
+                renew_handle.cancel().await;
+                let _ = destroy_session(client, &session_id).await;
+                bail!(
+                    "Timed out after {:?} waiting to acquire lock on {}",
+                    lock_timeout,
+                    key
+                );
+            }
+
+            task::sleep(Duration::from_millis(500)).await;
+        }
+
+        Ok(Lock {
+            client: client.clone(),
+            key: key.to_string(),
+            session_id,
+            renew_handle: Some(renew_handle),
+            released: false,
+        })
+    }
+
+    /// Release the lock key and destroy the backing session. Safe to call
+    /// at most once; prefer this over letting the guard drop so the lock
+    /// key frees up immediately instead of waiting out the TTL.
+    pub async fn release(mut self) -> anyhow::Result<()> {
+        self.released = true;
+        if let Some(handle) = self.renew_handle.take() {
+            handle.cancel().await;
+        }
+
+        let lock_path = format!("/v1/kv/{}/.lock", self.key);
+        let release_result = surf2anyhow(
+            self.client.put(&format!("{}?release={}", &lock_path, &self.session_id)).await,
+        )
+        .context("Releasing Consul lock");
+
+        // Destroy the session regardless of whether the release PUT
+        // succeeded, so a failed release doesn't leak the session until its
+        // TTL expires.
+        let destroy_result = destroy_session(&self.client, &self.session_id).await;
+
+        release_result?;
+        destroy_result
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if !self.released {
+            // Stop renewing, or the detached renewal task would keep the
+            // session (and the lock key) alive instead of letting it expire.
+            if let Some(handle) = self.renew_handle.take() {
+                task::spawn(handle.cancel());
+            }
+            eprintln!(
+                "warning: lock on {} dropped without being released; session {} will expire on its own TTL",
+                self.key, self.session_id
+            );
+        }
+    }
+}
+
+async fn create_session(client: &ConsulClient, ttl: Duration) -> anyhow::Result<String> {
+    let body = SessionCreateRequest {
+        ttl: format!("{}s", ttl.as_secs()),
+        behavior: "delete".to_string(),
+        lock_delay: "0s".to_string(),
+    };
+
+    let mut response = surf2anyhow(
+        client.put("/v1/session/create")
+            .body_json(&body)
+            .context("Building session create request")?
+            .await,
+    )?;
+
+    let parsed: SessionCreateResponse = response
+        .body_json()
+        .await
+        .context("Parsing Consul session/create response")?;
+
+    Ok(parsed.ID)
+}
+
+async fn destroy_session(client: &ConsulClient, session_id: &str) -> anyhow::Result<()> {
+    surf2anyhow(
+        client.put(&format!("/v1/session/destroy/{}", session_id)).await,
+    )?;
+
+    Ok(())
+}
+
+fn spawn_renewal(client: ConsulClient, session_id: String, ttl: Duration) -> task::JoinHandle<()> {
+    let interval = ttl / 2;
+    task::spawn(async move {
+        loop {
+            task::sleep(interval).await;
+            let result = surf2anyhow(
+                client.put(&format!("/v1/session/renew/{}", &session_id)).await,
+            );
+
+            if let Err(e) = result {
+                eprintln!("warning: failed to renew Consul session {}: {}", session_id, e);
+            }
+        }
+    })
+}
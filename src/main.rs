@@ -1,6 +1,7 @@
 #![feature(async_closure)]
 use anyhow::{self, bail, Context};
 use async_std::fs as afs;
+use async_std::task;
 use chrono::prelude::*;
 use serde_derive::{ Deserialize, Serialize };
 use serde_yaml;
@@ -8,11 +9,18 @@ use std::collections::{ HashSet, HashMap };
 use std::fs::DirBuilder;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 use structopt::clap::AppSettings::*;
 use structopt::StructOpt;
 use surf;
 use thiserror::Error;
 
+mod consul;
+mod health;
+mod locking;
+mod serve;
+mod watch;
+
 #[derive(StructOpt)]
 #[structopt(name = "vongform", about = "Manage data for a helm umbrella chart stored in consul. Update service versions and emit the chart.")]
 #[structopt(global_setting(ColoredHelp))]
@@ -30,9 +38,51 @@ struct Options {
     #[structopt(short, long,
         help = "the fully-qualified url of the helm chart repository to use; defaults to VONGFORM_DEFAULT_REPOSITORY",
     )]
-    repository: Option<String>
+    repository: Option<String>,
+
+    #[structopt(long, default_value = "15",
+        help = "how long, in seconds, to wait to acquire the Consul lock on `umbrella' before giving up",
+    )]
+    lock_timeout: u64,
+
+    #[structopt(long,
+        help = "after the initial update, keep running and re-emit the chart whenever `umbrella' or any override changes in Consul",
+    )]
+    watch: bool,
+
+    #[structopt(long, default_value = "5m",
+        help = "how long each blocking Consul query in --watch mode should wait for a change before retrying",
+    )]
+    wait: String,
+
+    #[structopt(long, default_value = "5",
+        help = "how many times to retry the umbrella CAS write if another writer races us, before giving up",
+    )]
+    max_retries: u32,
+
+    #[structopt(long,
+        help = "before emitting the chart, confirm each dependency has a passing Consul catalog instance advertising the requested version",
+    )]
+    require_healthy: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>
 }
 
+#[derive(StructOpt)]
+enum Command {
+    /// Run vongform as a long-lived HTTP service exposing a set/emit API,
+    /// so CI/CD can PUT/DELETE dependencies over HTTP instead of shelling
+    /// out to the binary.
+    Serve {
+        #[structopt(long, default_value = "127.0.0.1:8080",
+            help = "address to listen on, e.g. 0.0.0.0:8080",
+        )]
+        listen: String
+    }
+}
+
+#[derive(Clone)]
 struct ServiceSetting {
     name: String,
     version: Option<String>
@@ -76,7 +126,7 @@ struct RequirementsYAML {
     dependencies: Vec<Requirement>
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Requirement {
     name: String,
     version: String,
@@ -116,11 +166,140 @@ async fn main() -> anyhow::Result<()> {
         bail!("Could not parse one of the settings (all settings require service name and an equals-sign)");
     }
 
-    let consul_url = std::env::var("CONSUL_HTTP_ADDR").ok().unwrap_or_else(
-        || "http://localhost:8500".to_string()
-    );
+    let client = consul::ConsulClient::from_env()?;
+
+    if let Some(Command::Serve { listen }) = &opts.command {
+        return serve::run(
+            client,
+            opts.output.clone().unwrap(),
+            opts.repository.clone(),
+            Duration::from_secs(opts.lock_timeout),
+            opts.max_retries,
+            opts.require_healthy,
+            listen,
+        ).await;
+    }
+
+    commit_settings_locked(
+        &client,
+        opts.output.as_ref().unwrap(),
+        &settings,
+        &opts.repository,
+        Duration::from_secs(opts.lock_timeout),
+        opts.max_retries,
+        opts.require_healthy,
+    ).await?;
+
+    if opts.watch {
+        let wait = humantime_to_duration(&opts.wait)?;
+        watch::run(&client, opts.output.as_ref().unwrap(), wait).await?;
+    }
+
+    Ok(())
+}
+
+/// Acquire the `umbrella` lock, run `commit_settings`, and release the lock
+/// regardless of the result. Shared by the CLI's one-shot update and the
+/// `serve` subcommand's HTTP handlers so the Consul read-modify-write
+/// semantics live in exactly one place.
+pub(crate) async fn commit_settings_locked(
+    client: &consul::ConsulClient,
+    output: &PathBuf,
+    settings: &[ServiceSetting],
+    repository: &Option<String>,
+    lock_timeout: Duration,
+    max_retries: u32,
+    require_healthy: bool,
+) -> anyhow::Result<MaterializeResult> {
+    let lock = locking::Lock::acquire(client, "umbrella", Duration::from_secs(15), lock_timeout).await?;
+    let result = commit_settings(client, output, settings, repository, max_retries, require_healthy).await;
+    lock.release().await?;
+    result
+}
+
+async fn commit_settings(
+    client: &consul::ConsulClient,
+    output: &PathBuf,
+    settings: &[ServiceSetting],
+    repository: &Option<String>,
+    max_retries: u32,
+    require_healthy: bool,
+) -> anyhow::Result<MaterializeResult> {
+    let mut attempt = 0;
+
+    loop {
+        let (body, results) = fetch_requirements(client).await?;
+        let results = apply_settings(results, settings, repository);
+
+        if require_healthy {
+            health::require_healthy(client, &results).await?;
+        }
+
+        // Only render the YAML here (cheap, local) rather than calling the
+        // full `materialize` (which fetches overrides and writes the chart
+        // to disk): a CAS conflict would otherwise rewrite the output
+        // directory once per losing attempt before the winning one.
+        let requirements_yaml = serde_yaml::to_string(&RequirementsYAML { dependencies: results.clone() })?;
+
+        let mut response = surf2anyhow(
+            client.put(&format!("/v1/kv/umbrella?cas={}", body[0].ModifyIndex))
+                .body_string(requirements_yaml).await)?;
+
+        let applied: bool = response.body_json().await.unwrap_or(false);
+        if applied {
+            return materialize(client, output, results).await;
+        }
+
+        attempt += 1;
+        if attempt > max_retries {
+            bail!(
+                "Gave up writing umbrella after {} CAS conflicts with other writers",
+                attempt
+            );
+        }
+
+        // Cap the shift so a large --max-retries can't overflow (and panic
+        // in debug / wrap in release) the backoff calculation.
+        let backoff = Duration::from_millis(100u64 * (1u64 << attempt.min(16)));
+        task::sleep(backoff).await;
+    }
+}
+
+/// Apply a batch of `--set` settings to a dependency list, adding, updating
+/// or removing entries as appropriate. Pure function of its inputs so the
+/// CAS retry loop in `commit_settings` can re-run it against a fresh read.
+fn apply_settings(mut results: Vec<Requirement>, settings: &[ServiceSetting], repository: &Option<String>) -> Vec<Requirement> {
+    for setting in settings {
+        let maybe_found = results.iter().enumerate().find(|(_idx, xs)| xs.name == setting.name);
+        match &setting.version {
+            Some(version) => {
+                if let Some((idx, _requirement)) = maybe_found {
+                    results[idx].version = version.clone();
+                    results[idx].repository = repository.clone().or_else(|| results[idx].repository.clone());
+                } else {
+                    results.push(Requirement {
+                        name: setting.name.clone(),
+                        version: version.clone(),
+                        repository: repository.clone()
+                    })
+                }
+            },
+            None => {
+                if let Some((idx, _requirement)) = maybe_found {
+                    results.remove(idx);
+                }
+            }
+        }
+    }
+
+    results
+}
 
-    let mut response = surf2anyhow(surf::get(format!("{}/v1/kv/umbrella", &consul_url)).await)?;
+/// Fetch and decode the `umbrella` key, falling back to an empty chart when
+/// it doesn't exist yet. Returns the raw Consul value alongside (so callers
+/// can read its `ModifyIndex`) together with the parsed dependency list.
+async fn fetch_requirements(client: &consul::ConsulClient) -> anyhow::Result<(Vec<ConsulValue>, Vec<Requirement>)> {
+    let mut response = client.get("/v1/kv/umbrella").await?;
     let body: Vec<ConsulValue> = if response.status().as_u16() == 200 {
         response.body_json().await?
     } else {
@@ -140,43 +319,27 @@ async fn main() -> anyhow::Result<()> {
 
     let raw_yaml = base64::decode(&body[0].Value).context("Attempting to decode Consul value from Base64")?;
     let requirements: RequirementsYAML = serde_yaml::from_slice(&raw_yaml[..]).context("Attempting to parse YAML from Consul")?;
-    let mut results = requirements.dependencies;
 
-    for setting in settings {
-        let maybe_found = results.iter().enumerate().find(|(_idx, xs)| xs.name == setting.name);
-        match setting.version {
-            Some(version) => {
-                if let Some((idx, _requirement)) = maybe_found {
-                    results[idx].version = version;
-                    results[idx].repository = opts.repository.clone().or_else(|| results[idx].repository.clone());
-                } else {
-                    results.push(Requirement {
-                        name: setting.name,
-                        version,
-                        repository: opts.repository.clone()
-                    })
-                }
-            },
-            None => {
-                if let Some((idx, _requirement)) = maybe_found {
-                    results.remove(idx);
-                }
-            }
-        }
-    }
+    Ok((body, requirements.dependencies))
+}
 
-    // TODO:
-    // - materialize the umbrella chart to disk
+pub(crate) struct MaterializeResult {
+    pub(crate) chart_version: String,
+}
 
+/// Fetch overrides and write `Chart.yaml`/`values.yaml`/`requirements.yaml`
+/// to `output`. Returns the generated `Chart.yaml` version string.
+async fn materialize(client: &consul::ConsulClient, output: &PathBuf, results: Vec<Requirement>) -> anyhow::Result<MaterializeResult> {
     let mut service_names = results.iter().map(|xs| &xs.name[..]).collect::<HashSet<&str>>();
     service_names.insert("global");
 
-    let overrides = get_overrides(service_names, &consul_url).await?;
+    let overrides = get_overrides(service_names, client).await?;
     let now: DateTime<Utc> = Utc::now();
     let compiled = RequirementsYAML { dependencies: results };
     let requirements_yaml = serde_yaml::to_string(&compiled)?;
+    let chart_version = format!("1.0.0-{}", now.timestamp());
 
-    let mut pb = PathBuf::from(opts.output.unwrap());
+    let mut pb = output.clone();
     DirBuilder::new().recursive(true).create(&pb)?;
 
     pb.push("Chart.yaml");
@@ -184,8 +347,8 @@ async fn main() -> anyhow::Result<()> {
 description: 'Umbrella chart, generated on {}'
 appVersion: '1.0'
 name: chart
-version: '1.0.0-{}'
-"#, now.to_rfc2822(), now.timestamp())).await?;
+version: '{}'
+"#, now.to_rfc2822(), chart_version)).await?;
     pb.pop();
 
     pb.push("values.yaml");
@@ -196,11 +359,24 @@ version: '1.0.0-{}'
     afs::write(&pb, &requirements_yaml[..]).await?;
     pb.pop();
 
-    let mut _response = surf2anyhow(surf::put(
-        format!("{}/v1/kv/umbrella?cas={}", &consul_url, body[0].ModifyIndex)
-    ).body_string(requirements_yaml).await)?;
+    Ok(MaterializeResult { chart_version })
+}
 
-    Ok(())
+/// Parse a Consul-style duration string (e.g. `"5m"`, `"30s"`) into a
+/// `Duration`. Consul only ever hands us back `s`/`m`/`h` suffixes for
+/// things like `wait=`, so that's all we need to support here.
+fn humantime_to_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let (digits, suffix) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: u64 = digits.parse().with_context(|| format!("Parsing duration {:?}", s))?;
+    let secs = match suffix {
+        "" | "s" => n,
+        "m" => n * 60,
+        "h" => n * 3600,
+        other => bail!("Unknown duration suffix {:?} in {:?}", other, s),
+    };
+
+    Ok(Duration::from_secs(secs))
 }
 
 #[derive(Serialize, Debug)]
@@ -210,53 +386,61 @@ enum Tree {
     Node(HashMap<String, Tree>)
 }
 
-async fn get_overrides<'a>(service_names: HashSet<&'a str>, consul_url: &'a str) -> anyhow::Result<Tree> {
+/// Build the override `Tree` from a single recursive read of the whole KV
+/// root, rather than one `?recurse=true` round-trip per service name. Keys
+/// whose first path segment isn't one of `service_names` are discarded
+/// locally; this trades a bit of wasted bandwidth on unrelated keys for one
+/// request instead of N, which matters once an umbrella chart has dozens of
+/// dependencies.
+async fn get_overrides<'a>(service_names: HashSet<&'a str>, client: &consul::ConsulClient) -> anyhow::Result<Tree> {
     let mut overrides = HashMap::new();
 
-    for service_name in service_names {
-        let mut response = surf2anyhow(surf::get(format!("{}/v1/kv/{}?recurse=true", &consul_url, service_name)).await)?;
-        if response.status().as_u16() != 200 {
+    let mut response = client.get("/v1/kv/?recurse=true").await?;
+    if response.status().as_u16() != 200 {
+        return Ok(Tree::Node(overrides));
+    }
+
+    let body: Vec<ConsulValue> = response.body_json().await?;
+
+    for consul_value in body {
+        let mut segments: Vec<_> = consul_value.Key.split('/').map(str::to_string).collect();
+        if segments.is_empty() || !service_names.contains(&segments[0][..]) {
             continue;
         }
 
-        let body: Vec<ConsulValue> = response.body_json().await?;
+        let bytes = match base64::decode(&consul_value.Value) {
+            Err(_) => continue,
+            Ok(b) => b,
+        };
 
-        for consul_value in body {
-            let mut segments: Vec<_> = consul_value.Key.split('/').map(str::to_string).collect();
-            let bytes = match base64::decode(&consul_value.Value) {
-                Err(_) => continue,
-                Ok(b) => b,
-            };
+        let decoded = match std::str::from_utf8(&bytes) {
 
-            let decoded = match std::str::from_utf8(&bytes) {
+            Err(_) => continue,
+            Ok(b) => b.to_string(),
+        };
 
-                Err(_) => continue,
-                Ok(b) => b.to_string(),
-            };
-
-            segments.reverse();
+        segments.reverse();
 
-            let mut current = &mut overrides;
+        let mut current = &mut overrides;
 
-            while segments.len() > 1 {
-                let level = segments.pop().unwrap();
-                let tmp = current.entry(level).and_modify(|e| {
-                    if let Tree::Leaf(_) = *e {
-                        *e = Tree::Node(HashMap::new())
-                    }
-                }).or_insert(Tree::Node(HashMap::new()));
+        while segments.len() > 1 {
+            let level = segments.pop().unwrap();
+            let tmp = current.entry(level).and_modify(|e| {
+                if let Tree::Leaf(_) = *e {
+                    *e = Tree::Node(HashMap::new())
+                }
+            }).or_insert(Tree::Node(HashMap::new()));
 
-                current = match tmp {
-                    Tree::Leaf(_) => {
-                        unreachable!("You can't get here from there.")
-                    },
-                    Tree::Node(x) => x
-                };
-            }
-            current.entry(segments.pop().unwrap())
-                .and_modify(|e| *e = Tree::Leaf(String::from(&decoded[..])))
-                .or_insert_with(|| Tree::Leaf(String::from(&decoded[..])));
+            current = match tmp {
+                Tree::Leaf(_) => {
+                    unreachable!("You can't get here from there.")
+                },
+                Tree::Node(x) => x
+            };
         }
+        current.entry(segments.pop().unwrap())
+            .and_modify(|e| *e = Tree::Leaf(String::from(&decoded[..])))
+            .or_insert_with(|| Tree::Leaf(String::from(&decoded[..])));
     }
 
     Ok(Tree::Node(overrides))
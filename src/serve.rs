@@ -0,0 +1,114 @@
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use tide::{Body, Request, Response, StatusCode};
+
+use crate::consul::ConsulClient;
+use crate::{commit_settings_locked, fetch_requirements, RequirementsYAML, ServiceSetting};
+
+#[derive(Clone)]
+struct State {
+    client: ConsulClient,
+    output: PathBuf,
+    repository: Option<String>,
+    lock_timeout: Duration,
+    max_retries: u32,
+    require_healthy: bool,
+}
+
+#[derive(Deserialize)]
+struct SetDependencyBody {
+    version: String,
+    repository: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EmitResponse {
+    version: String,
+}
+
+/// Run vongform as a long-lived HTTP service: `GET /dependencies`,
+/// `PUT`/`DELETE /dependencies/{name}`, and `POST /emit`. The `PUT` and
+/// `DELETE` routes are funneled through the same lock + CAS-retry path the
+/// CLI uses; `GET /dependencies` and `POST /emit` only read from Consul.
+pub async fn run(
+    client: ConsulClient,
+    output: PathBuf,
+    repository: Option<String>,
+    lock_timeout: Duration,
+    max_retries: u32,
+    require_healthy: bool,
+    listen: &str,
+) -> anyhow::Result<()> {
+    let state = State { client, output, repository, lock_timeout, max_retries, require_healthy };
+    let mut app = tide::with_state(state);
+
+    app.at("/healthcheck").get(healthcheck);
+    app.at("/dependencies").get(get_dependencies);
+    app.at("/dependencies/:name").put(put_dependency);
+    app.at("/dependencies/:name").delete(delete_dependency);
+    app.at("/emit").post(post_emit);
+
+    app.listen(listen).await?;
+    Ok(())
+}
+
+async fn healthcheck(_req: Request<State>) -> tide::Result {
+    Ok(Response::builder(StatusCode::Ok).body("ok").build())
+}
+
+async fn get_dependencies(req: Request<State>) -> tide::Result {
+    let (_body, results) = fetch_requirements(&req.state().client).await.map_err(to_tide_error)?;
+    let compiled = RequirementsYAML { dependencies: results };
+    Body::from_json(&compiled).map(Into::into)
+}
+
+async fn put_dependency(mut req: Request<State>) -> tide::Result {
+    let name = req.param("name")?.to_string();
+    let body: SetDependencyBody = req.body_json().await?;
+    let state = req.state().clone();
+    let repository = body.repository.or_else(|| state.repository.clone());
+
+    commit_settings_locked(
+        &state.client,
+        &state.output,
+        &[ServiceSetting { name, version: Some(body.version) }],
+        &repository,
+        state.lock_timeout,
+        state.max_retries,
+        state.require_healthy,
+    ).await.map_err(to_tide_error)?;
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn delete_dependency(req: Request<State>) -> tide::Result {
+    let name = req.param("name")?.to_string();
+    let state = req.state();
+
+    commit_settings_locked(
+        &state.client,
+        &state.output,
+        &[ServiceSetting { name, version: None }],
+        &None,
+        state.lock_timeout,
+        state.max_retries,
+        state.require_healthy,
+    ).await.map_err(to_tide_error)?;
+
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn post_emit(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    let (_body, results) = fetch_requirements(&state.client).await.map_err(to_tide_error)?;
+    let materialized = crate::materialize(&state.client, &state.output, results)
+        .await
+        .map_err(to_tide_error)?;
+
+    Body::from_json(&EmitResponse { version: materialized.chart_version }).map(Into::into)
+}
+
+fn to_tide_error(e: anyhow::Error) -> tide::Error {
+    tide::Error::from_str(StatusCode::InternalServerError, e.to_string())
+}
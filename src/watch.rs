@@ -0,0 +1,137 @@
+use anyhow::{bail, Context};
+use async_std::channel;
+use async_std::task;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::consul::ConsulClient;
+use crate::{fetch_requirements, materialize};
+
+/// Run forever, re-materializing the umbrella chart to `output` any time
+/// `umbrella` or one of its override prefixes changes in Consul.
+///
+/// Each watched key gets its own long-lived background task blocking on
+/// Consul's `?index=<N>&wait=<wait>` semantics; changes are funneled through
+/// a channel so a burst of writes across several keys collapses into one
+/// chart rewrite instead of one per key. Watchers persist across rewrites —
+/// only keys that appear or disappear from the dependency set cause a
+/// watcher to be spawned or cancelled — since tearing every watcher down and
+/// reseeding at index 0 on every rewrite would make `index=0` fire an
+/// immediate spurious "change" and busy-loop the whole feature.
+pub async fn run(client: &ConsulClient, output: &PathBuf, wait: Duration) -> anyhow::Result<()> {
+    let (tx, rx) = channel::unbounded();
+    let mut watchers: HashMap<String, task::JoinHandle<()>> = HashMap::new();
+
+    loop {
+        let (_body, results) = fetch_requirements(client).await?;
+
+        let mut watched: HashSet<String> = results.iter().map(|xs| xs.name.clone()).collect();
+        watched.insert("global".to_string());
+        watched.insert("umbrella".to_string());
+
+        let stale: Vec<String> = watchers.keys().filter(|k| !watched.contains(*k)).cloned().collect();
+        for key in stale {
+            if let Some(handle) = watchers.remove(&key) {
+                handle.cancel().await;
+            }
+        }
+
+        for key in &watched {
+            if watchers.contains_key(key) {
+                continue;
+            }
+
+            // "umbrella" is a single KV entry, not a prefix: polling it with
+            // `recurse=true` would also pick up its own `.lock` subkey, so
+            // every lock acquire/release by a concurrent writer would bump
+            // its recursive index and fire a spurious rewrite. Only the
+            // override prefixes need recursion.
+            let recurse = key != "umbrella";
+
+            // Seed with the key's current index so the watcher's first
+            // blocking poll waits for a real change instead of firing on
+            // the 0 -> current-index transition every key starts at.
+            let seed_index = blocking_get(client, key, recurse, 0, Duration::from_secs(0)).await.unwrap_or(0);
+
+            let client = client.clone();
+            let tx = tx.clone();
+            let key_owned = key.clone();
+            watchers.insert(key.clone(), task::spawn(async move {
+                if let Err(e) = watch_key(&client, &key_owned, recurse, wait, seed_index, tx).await {
+                    eprintln!("warning: watcher for {} exited: {}", key_owned, e);
+                }
+            }));
+        }
+
+        // Block until the first change comes in, then drain the channel for
+        // a short debounce window so a burst of writes produces one rewrite.
+        if rx.recv().await.is_err() {
+            bail!("All Consul watchers exited unexpectedly");
+        }
+
+        loop {
+            match async_std::future::timeout(Duration::from_millis(500), rx.recv()).await {
+                Ok(Ok(_)) => continue,
+                _ => break,
+            }
+        }
+
+        let (_body, results) = fetch_requirements(client).await?;
+        materialize(client, output, results).await?;
+    }
+}
+
+/// Long-poll a single Consul key starting from `index`, sending `key`'s name
+/// down `changed` every time its `X-Consul-Index` advances past the
+/// last-seen value. `recurse` should be set for prefixes that cover more
+/// than one KV entry; a single-entry key like "umbrella" should leave it
+/// unset so unrelated subkeys (e.g. its `.lock`) can't trigger a spurious
+/// change.
+async fn watch_key(
+    client: &ConsulClient,
+    key: &str,
+    recurse: bool,
+    wait: Duration,
+    mut index: u64,
+    changed: channel::Sender<String>,
+) -> anyhow::Result<()> {
+    loop {
+        let new_index = blocking_get(client, key, recurse, index, wait).await?;
+
+        if new_index < index {
+            // Consul index went backwards (e.g. a snapshot restore); reset
+            // and re-poll from scratch rather than trusting the old value.
+            index = 0;
+            continue;
+        }
+
+        if new_index != index {
+            index = new_index;
+            if changed.send(key.to_string()).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn blocking_get(client: &ConsulClient, key: &str, recurse: bool, index: u64, wait: Duration) -> anyhow::Result<u64> {
+    let response = client
+        .get(&format!(
+            "/v1/kv/{}?recurse={}&index={}&wait={}s",
+            key,
+            recurse,
+            index,
+            wait.as_secs()
+        ))
+        .await?;
+
+    let header = response
+        .header("X-Consul-Index")
+        .context("Consul response missing X-Consul-Index header")?;
+
+    header
+        .as_str()
+        .parse()
+        .with_context(|| format!("Parsing X-Consul-Index {:?}", header.as_str()))
+}